@@ -7,6 +7,7 @@ extern crate gfx_app;
 extern crate cgmath;
 extern crate obj;
 extern crate hprof;
+extern crate image;
 
 use cgmath::SquareMatrix;
 use cgmath::{Matrix4, Vector4, Vector3, Vector2, Transform, Point3};
@@ -19,11 +20,14 @@ use gfx::memory::{Usage, Access};
 use gfx_app::ColorFormat;
 
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Instant;
 
 mod rasterizer;
+mod texture;
 
 use rasterizer::*;
+use texture::*;
 
 #[derive(Copy, Clone)]
 pub struct Color(Vector3<f32>);
@@ -56,9 +60,14 @@ pub fn vertex_shader(input: Vertex, mat: Matrix4<f32>) -> (Uv, Vector4<f32>) {
     (Uv(input.uv), mat * input.pos.extend(1f32))
 }
 
+/// Textures bound for the duration of a draw call.
+pub struct Samplers {
+    tex0: BoundTexture
+}
+
 #[inline]
-pub fn fragment_shader(fragment: Vector2<f32>, input: Uv) -> Vector4<f32> {
-    Vector4::new(input.0.x, input.0.y, 1f32, 1f32)
+pub fn fragment_shader(fragment: Vector2<f32>, input: Uv, samplers: &Samplers) -> Vector4<f32> {
+    samplers.tex0.sample(input.0)
 }
 
 
@@ -90,12 +99,25 @@ struct App<R: gfx::Resources> {
     bundle: Bundle<R, blit::Data<R>>,
     blit_texture: gfx::handle::Texture<R, gfx::format::R8_G8_B8_A8>,
     rasterizer: Rasterizer,
-    pipeline: Pipeline<Vertex, Uv>,
+    pipeline: Pipeline<Vertex, Uv, Samplers>,
+    samplers: Samplers,
     model: Vec<Vertex>,
     start_time: Instant,
     prof: hprof::Profiler
 }
 
+/// Loads an RGBA8 `Texture` from an image file on disk.
+fn load_texture(path: &Path) -> Texture {
+    let image = image::open(path).expect("failed to load texture").to_rgba();
+    let (width, height) = image.dimensions();
+    let pixels = image.into_raw()
+        .chunks(4)
+        .map(|p| [p[0], p[1], p[2], p[3]])
+        .collect();
+
+    Texture::new(width, height, pixels)
+}
+
 impl<R> gfx_app::Application<R> for App<R>
         where R: gfx::Resources
 {
@@ -144,8 +166,16 @@ impl<R> gfx_app::Application<R> for App<R>
             bundle: Bundle::new(slice, pso, data),
             blit_texture: texture,
 
-            rasterizer: Rasterizer::new(SIZE.0 as _, SIZE.1 as _),
+            rasterizer: {
+                let mut rasterizer = Rasterizer::new(SIZE.0 as _, SIZE.1 as _);
+                rasterizer.set_aa_mode(AaMode::X4);
+                rasterizer
+            },
             pipeline: Pipeline::new(vertex_shader, fragment_shader),
+            samplers: Samplers {
+                tex0: BoundTexture::new(Arc::new(load_texture(Path::new("./data/spot_texture.png"))),
+                                        Sampler::new(Filter::Bilinear, WrapMode::Repeat))
+            },
             model: {
                 let object = obj::load::<obj::SimplePolygon>(Path::new("./data/spot.obj")).unwrap();
                 let indices = object.object_iter().next().unwrap().group_iter().next().unwrap().indices();
@@ -189,7 +219,7 @@ impl<R> gfx_app::Application<R> for App<R>
         self.prof.leave();
 
         self.prof.enter_noguard("draw");
-        self.rasterizer.draw(&mut self.pipeline, &self.model, &self.prof);
+        self.rasterizer.draw(&mut self.pipeline, &self.model, &self.samplers, &self.prof);
         self.prof.leave();
 
         self.prof.enter_noguard("blit");
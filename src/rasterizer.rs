@@ -4,8 +4,31 @@ use cgmath::{Matrix4, Vector4, Vector3, Vector2};
 
 use hprof;
 
+use std::cmp;
 use std::f32;
 use std::mem;
+use std::thread;
+
+/// Supersampling level used for coverage antialiasing. The depthbuffer is
+/// sized `width*height*samples()` so each sub-sample gets its own depth test.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AaMode {
+    Single,
+    X2,
+    X4,
+    X8,
+}
+
+impl AaMode {
+    fn samples(&self) -> usize {
+        match *self {
+            AaMode::Single => 1,
+            AaMode::X2 => 2,
+            AaMode::X4 => 4,
+            AaMode::X8 => 8,
+        }
+    }
+}
 
 pub struct Rasterizer {
     width: u32,
@@ -13,7 +36,8 @@ pub struct Rasterizer {
     backbuffer: Vec<[u8; 4]>,
     depthbuffer: Vec<u32>,
     view: Matrix4<f32>,
-    proj: Matrix4<f32>
+    proj: Matrix4<f32>,
+    aa_mode: AaMode
 }
 
 impl Rasterizer {
@@ -25,11 +49,12 @@ impl Rasterizer {
             depthbuffer: vec![0; (width * height) as usize],
             view: Matrix4::<f32>::identity(),
             proj: Matrix4::<f32>::identity(),
+            aa_mode: AaMode::Single,
         }
     }
 
-    pub fn draw<I, O>(&mut self, pipeline: &mut Pipeline<I, O>, vertices: &[I], prof: &hprof::Profiler)
-            where I: Copy + Clone, O: Copy + Clone + Blend
+    pub fn draw<I, O, S>(&mut self, pipeline: &mut Pipeline<I, O, S>, vertices: &[I], samplers: &S, prof: &hprof::Profiler)
+            where I: Copy + Clone, O: Copy + Clone + Blend + Send + Sync, S: Sync
     {
         pipeline.process(self.width,
                          self.height,
@@ -38,6 +63,8 @@ impl Rasterizer {
                          self.proj,
                          &mut self.backbuffer,
                          &mut self.depthbuffer,
+                         samplers,
+                         self.aa_mode,
                          &prof);
     }
 
@@ -49,6 +76,11 @@ impl Rasterizer {
         self.proj = mat;
     }
 
+    pub fn set_aa_mode(&mut self, mode: AaMode) {
+        self.aa_mode = mode;
+        self.depthbuffer = vec![u32::max_value(); (self.width * self.height) as usize * mode.samples()];
+    }
+
     pub fn clear(&mut self) {
         for x in &mut self.backbuffer {
             x[0] = 0;
@@ -74,6 +106,141 @@ impl Rasterizer {
 
 pub trait Blend {
     fn blend(a: Self, aw: f32, b: Self, bw: f32, c: Self, cw: f32) -> Self;
+
+    /// Two-point interpolation along an edge, used by near-plane clipping.
+    /// Derived from the three-way `blend` by weighting `a` and `b` only.
+    fn lerp(a: Self, b: Self, t: f32) -> Self where Self: Copy {
+        Self::blend(a, 1f32 - t, b, t, a, 0f32)
+    }
+}
+
+/// Porter-Duff / separable blend modes applied when compositing a shaded
+/// fragment onto the backbuffer. Operates on premultiplied 8-bit RGBA.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BlendMode {
+    SrcOver,
+    DstOver,
+    Src,
+    Dst,
+    Clear,
+    Add,
+    Screen,
+    Multiply,
+    Darken,
+    Lighten,
+    Xor,
+}
+
+/// Multiplies two 8-bit channels and rounds back down to 8 bits without
+/// going through floating point, i.e. `(a * b) / 255` rounded to nearest.
+#[inline]
+fn muldiv255(a: u8, b: u8) -> u8 {
+    let a = a as u32;
+    let b = b as u32;
+    let x = a * b + 128;
+    ((x + (x >> 8)) >> 8) as u8
+}
+
+#[inline]
+fn composite(mode: BlendMode, src: [u8; 4], dst: [u8; 4]) -> [u8; 4] {
+    // premultiply the incoming fragment color by its own alpha
+    let src = [muldiv255(src[0], src[3]),
+               muldiv255(src[1], src[3]),
+               muldiv255(src[2], src[3]),
+               src[3]];
+
+    let mut out = [0u8; 4];
+    for i in 0..4 {
+        let s = src[i] as i32;
+        let d = dst[i] as i32;
+
+        out[i] = match mode {
+            BlendMode::SrcOver => s + muldiv255(dst[i], 255 - src[3]) as i32,
+            BlendMode::DstOver => d + muldiv255(src[i], 255 - dst[3]) as i32,
+            BlendMode::Src => s,
+            BlendMode::Dst => d,
+            BlendMode::Clear => 0,
+            BlendMode::Add => cmp::min(s + d, 255),
+            BlendMode::Screen => 255 - muldiv255(255 - src[i], 255 - dst[i]) as i32,
+            BlendMode::Multiply => muldiv255(src[i], dst[i]) as i32,
+            BlendMode::Darken => cmp::min(s, d),
+            BlendMode::Lighten => cmp::max(s, d),
+            // Separable Porter-Duff Xor: src*(1-Da) + dst*(1-Sa), on the
+            // premultiplied values. Two fully-opaque overlapping fragments
+            // have no non-overlapping region, so this correctly resolves
+            // to transparent black.
+            BlendMode::Xor => muldiv255(src[i], 255 - dst[3]) as i32
+                + muldiv255(dst[i], 255 - src[3]) as i32,
+        }.max(0).min(255) as u8;
+    }
+    out
+}
+
+const NEAR_EPSILON: f32 = 1e-4;
+
+/// Clips a single triangle against the near plane (`w >= NEAR_EPSILON`) using
+/// Sutherland-Hodgman, re-triangulating the resulting 0/3/4-gon as a fan and
+/// appending it to the given output caches. Keeps `rasterize` from having to
+/// deal with vertices behind (or at) the camera, whose `x/-z` projection
+/// would otherwise blow up.
+fn clip_near<O: Copy + Clone + Blend>(verts: [Vector4<f32>; 3],
+                                       attrs: [O; 3],
+                                       invw: [f32; 3],
+                                       out_verts: &mut Vec<Vector4<f32>>,
+                                       out_attrs: &mut Vec<O>,
+                                       out_invw: &mut Vec<f32>)
+{
+    #[inline]
+    fn dist(v: Vector4<f32>) -> f32 {
+        v.w - NEAR_EPSILON
+    }
+
+    let mut poly_v: Vec<Vector4<f32>> = Vec::with_capacity(4);
+    let mut poly_a: Vec<O> = Vec::with_capacity(4);
+    let mut poly_w: Vec<f32> = Vec::with_capacity(4);
+
+    for i in 0..3 {
+        let (cur_v, next_v) = (verts[i], verts[(i + 1) % 3]);
+        let (cur_a, next_a) = (attrs[i], attrs[(i + 1) % 3]);
+        let (cur_w, next_w) = (invw[i], invw[(i + 1) % 3]);
+
+        let (d0, d1) = (dist(cur_v), dist(next_v));
+
+        if d0 >= 0f32 {
+            poly_v.push(cur_v);
+            poly_a.push(cur_a);
+            poly_w.push(cur_w);
+        }
+
+        if (d0 >= 0f32) != (d1 >= 0f32) {
+            let t = d0 / (d0 - d1);
+            let new_w = cur_v.w + (next_v.w - cur_v.w) * t;
+
+            poly_v.push(Vector4::new(cur_v.x + (next_v.x - cur_v.x) * t,
+                                     cur_v.y + (next_v.y - cur_v.y) * t,
+                                     cur_v.z + (next_v.z - cur_v.z) * t,
+                                     new_w));
+            poly_a.push(Blend::lerp(cur_a, next_a, t));
+            // `1/w` is not affine in `t`, so this must come from the
+            // freshly-lerped clip-space `w` above, not from lerping
+            // `cur_w`/`next_w` (which are already reciprocals).
+            poly_w.push(1.0 / new_w);
+        }
+    }
+
+    for i in 1..poly_v.len().saturating_sub(1) {
+        out_verts.push(poly_v[0]);
+        out_verts.push(poly_v[i]);
+        out_verts.push(poly_v[i + 1]);
+
+        out_attrs.push(poly_a[0]);
+        out_attrs.push(poly_a[i]);
+        out_attrs.push(poly_a[i + 1]);
+
+        out_invw.push(poly_w[0]);
+        out_invw.push(poly_w[i]);
+        out_invw.push(poly_w[i + 1]);
+    }
 }
 
 struct Depth(f32);
@@ -84,166 +251,371 @@ impl Blend for Depth {
     }
 }
 
+/// Side length in pixels of the tiles triangles are binned into.
+const TILE_SIZE: i32 = 32;
+
+#[inline]
+fn edge_function(a: Vector2<i32>, b: Vector2<i32>, c: Vector2<i32>) -> i32 {
+    (c.x - a.x) * (b.y - a.y) - (c.y - a.y) * (b.x - a.x)
+}
+
+#[inline]
+fn edge_step(a: Vector2<i32>, b: Vector2<i32>, step: i32) -> i32 {
+    (b.y - a.y) * step
+}
+
+/// Sub-pixel offsets (in 1/256ths of a pixel, i.e. shifted left 8 bits) used
+/// for ordered-grid supersampling, one set per `AaMode`. Laid out as a
+/// regular grid rather than a rotated/jittered pattern, since the edge tests
+/// below are evaluated directly against these fixed-point offsets.
+fn sample_offsets(mode: AaMode) -> &'static [(i32, i32)] {
+    match mode {
+        AaMode::Single => &[(128, 128)],
+        AaMode::X2 => &[(64, 64), (192, 192)],
+        AaMode::X4 => &[(64, 64), (192, 64), (64, 192), (192, 192)],
+        AaMode::X8 => &[(32, 64), (96, 64), (160, 64), (224, 64),
+                        (32, 192), (96, 192), (160, 192), (224, 192)],
+    }
+}
+
+/// Everything about a triangle that's constant across its pixels, computed
+/// once during binning and reused by every tile/thread that touches it.
+struct BinnedTriangle {
+    p1: Vector2<i32>,
+    p2: Vector2<i32>,
+    p3: Vector2<i32>,
+    min: Vector2<i32>,
+    max: Vector2<i32>,
+    inv_area: f32,
+    x_step: [i32; 3],
+    y_step: [i32; 3],
+    // index of this triangle's first vertex in the triangles/attributes/invw arrays
+    base: usize
+}
+
 pub type VertexShader<I, O> = fn(I, Matrix4<f32>) -> (O, Vector4<f32>);
-pub type FragmentShader<I> = fn(Vector2<f32>, I) -> Vector4<f32>;
+// `S` is whatever textures/uniforms the fragment shader has bound, e.g. an
+// app-defined `Samplers` struct so shaders can do `samplers.tex0.sample(uv)`.
+pub type FragmentShader<I, S> = fn(Vector2<f32>, I, &S) -> Vector4<f32>;
 
-pub struct Pipeline<I, O> {
+pub struct Pipeline<I, O, S> {
     vertex_fn: VertexShader<I, O>,
-    frag_fn: FragmentShader<O>,
+    frag_fn: FragmentShader<O, S>,
 
     frag_cache: Vec<O>,
-    vertex_cache: Vec<Vector4<f32>>
+    vertex_cache: Vec<Vector4<f32>>,
+    invw_cache: Vec<f32>,
+    blend_mode: BlendMode,
+
+    // near-plane clipped triangles, re-triangulated as fans; rasterize()
+    // reads from these instead of the raw transform output
+    clip_vertex_cache: Vec<Vector4<f32>>,
+    clip_frag_cache: Vec<O>,
+    clip_invw_cache: Vec<f32>
 }
 
-impl<I, O> Pipeline<I, O>
-        where I: Copy + Clone, O: Copy + Clone + Blend {
-    pub fn new(vertex: VertexShader<I, O>, frag: FragmentShader<O>) -> Self {
+impl<I, O, S> Pipeline<I, O, S>
+        where I: Copy + Clone, O: Copy + Clone + Blend + Send + Sync, S: Sync {
+    pub fn new(vertex: VertexShader<I, O>, frag: FragmentShader<O, S>) -> Self {
         Pipeline {
             vertex_fn: vertex,
             frag_fn: frag,
 
             frag_cache: Vec::with_capacity(4096),
-            vertex_cache: Vec::with_capacity(4096)
+            vertex_cache: Vec::with_capacity(4096),
+            invw_cache: Vec::with_capacity(4096),
+            blend_mode: BlendMode::Src,
+
+            clip_vertex_cache: Vec::with_capacity(4096),
+            clip_frag_cache: Vec::with_capacity(4096),
+            clip_invw_cache: Vec::with_capacity(4096)
         }
     }
 
-    #[inline]
+    pub fn set_blend_mode(&mut self, mode: BlendMode) {
+        self.blend_mode = mode;
+    }
+
     fn rasterize(width: u32,
                  height: u32,
                  triangles: &Vec<Vector4<f32>>,
                  attributes: &Vec<O>,
+                 invw: &Vec<f32>,
                  backbuffer: &mut Vec<[u8; 4]>,
                  depth: &mut Vec<u32>,
-                 frag: FragmentShader<O>)
+                 frag: FragmentShader<O, S>,
+                 samplers: &S,
+                 blend_mode: BlendMode,
+                 aa_mode: AaMode)
     {
-        let width = width as i32;
-        let height = height as i32;
-
-        #[inline]
-        fn edge_function(a: Vector2<i32>, b: Vector2<i32>, c: Vector2<i32>) -> i32 {
-            (c.x - a.x) * (b.y - a.y) - (c.y - a.y) * (b.x - a.x)
-        }
+        let samples = aa_mode.samples();
+        let width_i = width as i32;
+        let height_i = height as i32;
 
         #[inline]
-        fn edge_step(a: Vector2<i32>, b: Vector2<i32>, step: i32) -> i32 {
-            -(b.y-a.y)*step
-        }
-
-        #[inline]
-        fn map_coord(vec: Vector4<f32>, width: f32, height: f32, near: f32) -> Vector2<i32> {
+        fn map_coord(vec: Vector4<f32>, width: f32, height: f32) -> Vector2<i32> {
             let screen = Vector2::new(vec.x / -vec.z, vec.y / -vec.z);
-            let ndc = Vector2::new(2f32 * screen.x / width - 1f32,
-                                   2f32 * screen.y / height - 1f32);
             Vector2::new(((screen.x + 1f32) / 2f32 * width) as i32,
                          ((1f32 - screen.y) / 2f32 * height) as i32)
         }
 
-        let step = 256;
-        let mask = step - 1;
-
-        for triangle in (triangles.chunks(3).zip(attributes.chunks(3))).into_iter() {
-            if let (&[a, b, c], &[A, B, C]) = triangle {
-                // TODO: return coords as super-sampled coords in 1/256th
-                //       resolution
-
-                let p1 = map_coord(a, width as f32, height as f32, 0.1f32);
-                let p2 = map_coord(b, width as f32, height as f32, 0.1f32);
-                let p3 = map_coord(c, width as f32, height as f32, 0.1f32);
-
-                let mut min = Vector2::<i32>::new(i32::max_value(), i32::max_value());
-                let mut max = Vector2::<i32>::new(i32::min_value(), i32::min_value());
-
-                use std::cmp;
-
-                min.x = cmp::min(cmp::min(cmp::min(min.x, p1.x), p2.x), p3.x);
-                min.y = cmp::min(cmp::min(cmp::min(min.y, p1.y), p2.y), p3.y);
-
-                max.x = cmp::max(cmp::max(cmp::max(min.x, p1.x), p2.x), p3.x);
-                max.y = cmp::max(cmp::max(cmp::max(min.y, p1.y), p2.y), p3.y);
-
-                /*if (p1.x as i32) < min.x { min.x = p1.x as i32; }
-                if (p1.y as i32) < min.y { min.y = p1.y as i32; }
-                if (p2.x as i32) < min.x { min.x = p2.x as i32; }
-                if (p2.y as i32) < min.y { min.y = p2.y as i32; }
-                if (p3.x as i32) < min.x { min.x = p3.x as i32; }
-                if (p3.y as i32) <https://open.spotify.com/track/6X4mvrDbIckxVQlRm3HhtE min.y { min.y = p3.y as i32; }
-
-                if (p1.x as i32) > max.x { max.x = p1.x as i32; }
-                if (p1.y as i32) > max.y { max.y = p1.y as i32; }
-                if (p2.x as i32) > max.x { max.x = p2.x as i32; }
-                if (p2.y as i32) > max.y { max.y = p2.y as i32; }
-                if (p3.x as i32) > max.x { max.x = p3.x as i32; }
-                if (p3.y as i32) > max.y { max.y = p3.y as i32; }
-*/
-                if max.x > width  { max.x = width; }
-                if max.y > height { max.y = height; }
-                if min.x > width  { min.x = width; }
-                if min.y > height { min.y = height; }
-
-                if max.x < 0 { max.x = 0; }
-                if max.y < 0 { max.y = 0; }
-                if min.x < 0 { min.x = 0; }
-                if min.y < 0 { min.y = 0; } 
-
-                //println!("({}, {}), ({}, {})", min.x, min.y, max.x, max.y);
-                /*min.x = (min.x + mask) & !mask;
-                min.y = (min.y + mask) & !mask;
-                max.x = (max.x + mask) & !mask;
-                max.y = (max.y + mask) & !mask;*/
-
-                let (w1_step, w2_step, w3_step) = (edge_step(p1, p2, 1),
-                                                   edge_step(p2, p3, 1),
-                                                   edge_step(p3, p1, 1));
-
-                for x in (min.x..max.x) {
-                    for y in (min.y..max.y) {
-                        let point = Vector2::new(x, y);
-
-                        let area = edge_function(p1, p2, p3);
-                        if area <= 0 {
-                            continue;
-                        }
+        // phase 1: bin each triangle's screen-space bbox into the 32x32
+        // tiles it overlaps, precomputing everything that's constant across
+        // the triangle (edges, area, incremental steps) so phase 2 only
+        // ever adds/compares per pixel.
+        let tiles_x = ((width_i + TILE_SIZE - 1) / TILE_SIZE) as usize;
+        let tiles_y = ((height_i + TILE_SIZE - 1) / TILE_SIZE) as usize;
+
+        let mut binned: Vec<BinnedTriangle> = Vec::new();
+        let mut bins: Vec<Vec<usize>> = vec![Vec::new(); tiles_x * tiles_y];
+
+        for (tri_idx, chunk) in triangles.chunks(3).enumerate() {
+            if let &[a, b, c] = chunk {
+                let p1 = map_coord(a, width_i as f32, height_i as f32);
+                let p2 = map_coord(b, width_i as f32, height_i as f32);
+                let p3 = map_coord(c, width_i as f32, height_i as f32);
+
+                let area = edge_function(p1, p2, p3);
+                if area <= 0 {
+                    continue;
+                }
 
-                                        let (mut w1, mut w2, mut w3) = (edge_function(p1, p2, point),
-                                                edge_function(p2, p3, point),
-                                                edge_function(p3, p1, point));
+                let mut min = Vector2::new(cmp::min(cmp::min(p1.x, p2.x), p3.x),
+                                           cmp::min(cmp::min(p1.y, p2.y), p3.y));
+                let mut max = Vector2::new(cmp::max(cmp::max(p1.x, p2.x), p3.x),
+                                           cmp::max(cmp::max(p1.y, p2.y), p3.y));
 
+                min.x = cmp::max(0, cmp::min(min.x, width_i));
+                min.y = cmp::max(0, cmp::min(min.y, height_i));
+                max.x = cmp::max(0, cmp::min(max.x, width_i));
+                max.y = cmp::max(0, cmp::min(max.y, height_i));
 
-                        if w1 >= 0 && w2 >= 0 && w3 >= 0 {
-                            let w1 = w1 as f32 / area as f32;
-                            let w2 = w2 as f32 / area as f32;
-                            let w3 = w3 as f32 / area as f32;
+                if min.x >= max.x || min.y >= max.y {
+                    continue;
+                }
 
-                            let depth_val = ((f32::min(Blend::blend(Depth(a.z), w1 as f32, Depth(b.z), w2 as f32, Depth(c.z), w3 as f32).0,
-                                                       1000f32)
-                                                     / 1000f32) * u32::max_value() as f32) as u32;
+                let tri = BinnedTriangle {
+                    p1: p1, p2: p2, p3: p3,
+                    min: min, max: max,
+                    inv_area: 1f32 / area as f32,
+                    x_step: [edge_step(p1, p2, 1), edge_step(p2, p3, 1), edge_step(p3, p1, 1)],
+                    y_step: [-(p2.x - p1.x), -(p3.x - p2.x), -(p1.x - p3.x)],
+                    base: tri_idx * 3
+                };
+
+                binned.push(tri);
+                let idx = binned.len() - 1;
+
+                let tile_x0 = (min.x / TILE_SIZE) as usize;
+                let tile_x1 = cmp::min(tiles_x, (max.x - 1) as usize / TILE_SIZE as usize + 1);
+                let tile_y0 = (min.y / TILE_SIZE) as usize;
+                let tile_y1 = cmp::min(tiles_y, (max.y - 1) as usize / TILE_SIZE as usize + 1);
+
+                for ty in tile_y0..tile_y1 {
+                    for tx in tile_x0..tile_x1 {
+                        bins[ty * tiles_x + tx].push(idx);
+                    }
+                }
+            }
+        }
 
-                            let idx = (x + width * y) as usize;
+        // phase 2: split the framebuffer into horizontal bands of whole
+        // tile-rows (so each worker owns disjoint memory with no locking)
+        // and rasterize all tiles within a band on their own thread.
+        let num_bands = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).max(1);
+        let band_tiles = ((tiles_y + num_bands - 1) / num_bands).max(1);
+        let band_rows = band_tiles * TILE_SIZE as usize;
+
+        let binned = &binned;
+        let bins = &bins;
+
+        thread::scope(|scope| {
+            let mut bb_rest = &mut backbuffer[..];
+            let mut depth_rest = &mut depth[..];
+            let mut band = 0;
+
+            while !bb_rest.is_empty() {
+                let take = cmp::min(band_rows * width as usize, bb_rest.len());
+                let take_depth = cmp::min(take * samples, depth_rest.len());
+                let (bb_band, bb_tail) = bb_rest.split_at_mut(take);
+                let (depth_band, depth_tail) = depth_rest.split_at_mut(take_depth);
+                bb_rest = bb_tail;
+                depth_rest = depth_tail;
+
+                let ty_start = band * band_tiles;
+                let ty_end = cmp::min(tiles_y, ty_start + band_tiles);
+                let row_offset = ty_start * TILE_SIZE as usize;
+                band += 1;
+
+                if ty_start >= ty_end {
+                    continue;
+                }
 
-                            if depth_val < depth[idx] {
-                                depth[idx] = depth_val;
+                scope.spawn(move || {
+                    for ty in ty_start..ty_end {
+                        for tx in 0..tiles_x {
+                            for &tri_idx in &bins[ty * tiles_x + tx] {
+                                Self::rasterize_tile(&binned[tri_idx],
+                                                     tx as i32 * TILE_SIZE,
+                                                     ty as i32 * TILE_SIZE,
+                                                     width_i,
+                                                     row_offset,
+                                                     triangles,
+                                                     attributes,
+                                                     invw,
+                                                     bb_band,
+                                                     depth_band,
+                                                     frag,
+                                                     samplers,
+                                                     blend_mode,
+                                                     aa_mode);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    }
 
-                                let color = (frag)(Vector2::new(point.x as f32, point.y as f32), Blend::blend(A, w1 as f32, B, w2 as f32, C, w3 as f32));
+    #[inline]
+    fn rasterize_tile(tri: &BinnedTriangle,
+                      tile_x: i32,
+                      tile_y: i32,
+                      width: i32,
+                      row_offset: usize,
+                      triangles: &Vec<Vector4<f32>>,
+                      attributes: &Vec<O>,
+                      invw: &Vec<f32>,
+                      backbuffer: &mut [[u8; 4]],
+                      depth: &mut [u32],
+                      frag: FragmentShader<O, S>,
+                      samplers: &S,
+                      blend_mode: BlendMode,
+                      aa_mode: AaMode)
+    {
+        let offsets = sample_offsets(aa_mode);
+        let samples = offsets.len();
+        let inv_area_scaled = tri.inv_area / 256f32;
+        let (a, b, c) = (triangles[tri.base], triangles[tri.base + 1], triangles[tri.base + 2]);
+        let (A, B, C) = (attributes[tri.base], attributes[tri.base + 1], attributes[tri.base + 2]);
+        let (inv_w1, inv_w2, inv_w3) = (invw[tri.base], invw[tri.base + 1], invw[tri.base + 2]);
+
+        let x0 = cmp::max(tile_x, tri.min.x);
+        let x1 = cmp::min(tile_x + TILE_SIZE, tri.max.x);
+        let y0 = cmp::max(tile_y, tri.min.y);
+        let y1 = cmp::min(tile_y + TILE_SIZE, tri.max.y);
+
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
 
-                                backbuffer[idx][0] = (color.x * 255f32) as u8;
-                                backbuffer[idx][1] = (color.y * 255f32) as u8;
-                                backbuffer[idx][2] = (color.z * 255f32) as u8;
-                                backbuffer[idx][3] = (color.w * 255f32) as u8;
-                            }
+        let corner = Vector2::new(x0, y0);
+        let mut w1_row = edge_function(tri.p1, tri.p2, corner);
+        let mut w2_row = edge_function(tri.p2, tri.p3, corner);
+        let mut w3_row = edge_function(tri.p3, tri.p1, corner);
+
+        for y in y0..y1 {
+            let (mut w1, mut w2, mut w3) = (w1_row, w2_row, w3_row);
+
+            for x in x0..x1 {
+                let pix_idx = x as usize + width as usize * (y as usize - row_offset);
+
+                // evaluate all three edges at each sub-sample offset within
+                // this pixel; a sample is covered only if it's both inside
+                // the triangle and closer than whatever's already in its
+                // depth slot, so overlapping triangles antialias correctly.
+                let mut covered = 0usize;
+                let mut sum_ox = 0i64;
+                let mut sum_oy = 0i64;
+
+                for (s, &(ox, oy)) in offsets.iter().enumerate() {
+                    let sw1 = w1 as i64 * 256 + tri.x_step[0] as i64 * ox as i64 + tri.y_step[0] as i64 * oy as i64;
+                    let sw2 = w2 as i64 * 256 + tri.x_step[1] as i64 * ox as i64 + tri.y_step[1] as i64 * oy as i64;
+                    let sw3 = w3 as i64 * 256 + tri.x_step[2] as i64 * ox as i64 + tri.y_step[2] as i64 * oy as i64;
+
+                    if sw1 >= 0 && sw2 >= 0 && sw3 >= 0 {
+                        let nw1 = sw1 as f32 * inv_area_scaled;
+                        let nw2 = sw2 as f32 * inv_area_scaled;
+                        let nw3 = sw3 as f32 * inv_area_scaled;
+
+                        let depth_val = ((f32::min(Blend::blend(Depth(a.z), nw2, Depth(b.z), nw3, Depth(c.z), nw1).0,
+                                                   1000f32)
+                                                 / 1000f32) * u32::max_value() as f32) as u32;
+
+                        let didx = pix_idx * samples + s;
+
+                        if depth_val < depth[didx] {
+                            depth[didx] = depth_val;
+                            covered += 1;
+                            sum_ox += ox as i64;
+                            sum_oy += oy as i64;
                         }
                     }
                 }
+
+                if covered > 0 {
+                    // shade once at the centroid of the covered samples
+                    let cox = sum_ox / covered as i64;
+                    let coy = sum_oy / covered as i64;
+
+                    let cw1 = w1 as i64 * 256 + tri.x_step[0] as i64 * cox + tri.y_step[0] as i64 * coy;
+                    let cw2 = w2 as i64 * 256 + tri.x_step[1] as i64 * cox + tri.y_step[1] as i64 * coy;
+                    let cw3 = w3 as i64 * 256 + tri.x_step[2] as i64 * cox + tri.y_step[2] as i64 * coy;
+
+                    let lw1 = cw1 as f32 * inv_area_scaled;
+                    let lw2 = cw2 as f32 * inv_area_scaled;
+                    let lw3 = cw3 as f32 * inv_area_scaled;
+
+                    // perspective-correct weights: undo the screen-space
+                    // barycentric linearity by re-weighting with 1/w
+                    // (lw1 is the edge p1-p2 function, so it weights vertex 3;
+                    // lw2 weights vertex 1 and lw3 weights vertex 2)
+                    let persp = lw2 * inv_w1 + lw3 * inv_w2 + lw1 * inv_w3;
+                    let pw1 = lw2 * inv_w1 / persp;
+                    let pw2 = lw3 * inv_w2 / persp;
+                    let pw3 = lw1 * inv_w3 / persp;
+
+                    let color = (frag)(Vector2::new(x as f32, y as f32), Blend::blend(A, pw1, B, pw2, C, pw3), samplers);
+                    let coverage = covered as f32 / samples as f32;
+
+                    let src = [(color.x * 255f32) as u8,
+                               (color.y * 255f32) as u8,
+                               (color.z * 255f32) as u8,
+                               (color.w * coverage * 255f32) as u8];
+
+                    backbuffer[pix_idx] = composite(blend_mode, src, backbuffer[pix_idx]);
+                }
+
+                w1 += tri.x_step[0];
+                w2 += tri.x_step[1];
+                w3 += tri.x_step[2];
             }
+
+            w1_row += tri.y_step[0];
+            w2_row += tri.y_step[1];
+            w3_row += tri.y_step[2];
         }
     }
 
-    pub fn process(&mut self, width: u32, height: u32, vertices: &[I], view: Matrix4<f32>, proj: Matrix4<f32>, mut backbuffer: &mut Vec<[u8; 4]>, mut depth: &mut Vec<u32>, prof: &hprof::Profiler) {
+    pub fn process(&mut self, width: u32, height: u32, vertices: &[I], view: Matrix4<f32>, proj: Matrix4<f32>, mut backbuffer: &mut Vec<[u8; 4]>, mut depth: &mut Vec<u32>, samplers: &S, aa_mode: AaMode, prof: &hprof::Profiler) {
         prof.enter_noguard("transform");
         for &vertex in vertices {
             let (out, pos) = (self.vertex_fn)(vertex, proj * view * Matrix4::from_scale(15f32));
 
             self.frag_cache.push(out);
             self.vertex_cache.push(pos);
+            self.invw_cache.push(1f32 / pos.w);
+        }
+        prof.leave();
+
+        prof.enter_noguard("clip");
+        for triangle in (self.vertex_cache.chunks(3).zip(self.frag_cache.chunks(3)).zip(self.invw_cache.chunks(3))).into_iter() {
+            if let ((&[a, b, c], &[A, B, C]), &[wa, wb, wc]) = triangle {
+                clip_near([a, b, c], [A, B, C], [wa, wb, wc],
+                         &mut self.clip_vertex_cache,
+                         &mut self.clip_frag_cache,
+                         &mut self.clip_invw_cache);
+            }
         }
         prof.leave();
 
@@ -251,14 +623,22 @@ impl<I, O> Pipeline<I, O>
         prof.enter_noguard("rasterize");
         Self::rasterize(width,
                         height,
-                        &self.vertex_cache,
-                        &self.frag_cache,
+                        &self.clip_vertex_cache,
+                        &self.clip_frag_cache,
+                        &self.clip_invw_cache,
                         &mut backbuffer,
                         &mut depth,
-                        self.frag_fn);
+                        self.frag_fn,
+                        samplers,
+                        self.blend_mode,
+                        aa_mode);
         prof.leave();
 
         self.frag_cache.clear();
         self.vertex_cache.clear();
+        self.invw_cache.clear();
+        self.clip_vertex_cache.clear();
+        self.clip_frag_cache.clear();
+        self.clip_invw_cache.clear();
     }
 }
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use cgmath::{Vector2, Vector4};
+
+/// How out-of-range texture coordinates are resolved to a texel.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WrapMode {
+    Repeat,
+    Clamp
+}
+
+/// How a sample point between texels is resolved to a color.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Filter {
+    Nearest,
+    Bilinear
+}
+
+/// A CPU-side RGBA8 image that fragment shaders can sample from.
+pub struct Texture {
+    width: u32,
+    height: u32,
+    pixels: Vec<[u8; 4]>
+}
+
+impl Texture {
+    pub fn new(width: u32, height: u32, pixels: Vec<[u8; 4]>) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize);
+        Texture { width: width, height: height, pixels: pixels }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[inline]
+    fn texel(&self, x: i32, y: i32, wrap: WrapMode) -> Vector4<f32> {
+        let (w, h) = (self.width as i32, self.height as i32);
+        let (x, y) = match wrap {
+            WrapMode::Repeat => (x.rem_euclid(w), y.rem_euclid(h)),
+            WrapMode::Clamp => (x.max(0).min(w - 1), y.max(0).min(h - 1)),
+        };
+
+        let p = self.pixels[(x + y * w) as usize];
+        Vector4::new(p[0] as f32 / 255f32,
+                    p[1] as f32 / 255f32,
+                    p[2] as f32 / 255f32,
+                    p[3] as f32 / 255f32)
+    }
+}
+
+/// Addressing/filtering configuration used to read a `Texture`.
+#[derive(Copy, Clone, Debug)]
+pub struct Sampler {
+    pub filter: Filter,
+    pub wrap: WrapMode
+}
+
+impl Sampler {
+    pub fn new(filter: Filter, wrap: WrapMode) -> Self {
+        Sampler { filter: filter, wrap: wrap }
+    }
+
+    pub fn sample(&self, texture: &Texture, uv: Vector2<f32>) -> Vector4<f32> {
+        match self.filter {
+            Filter::Nearest => {
+                let x = (uv.x * texture.width() as f32) as i32;
+                let y = (uv.y * texture.height() as f32) as i32;
+                texture.texel(x, y, self.wrap)
+            }
+            Filter::Bilinear => {
+                let fx = uv.x * texture.width() as f32 - 0.5f32;
+                let fy = uv.y * texture.height() as f32 - 0.5f32;
+
+                let x0 = fx.floor() as i32;
+                let y0 = fy.floor() as i32;
+                let tx = fx - x0 as f32;
+                let ty = fy - y0 as f32;
+
+                let c00 = texture.texel(x0, y0, self.wrap);
+                let c10 = texture.texel(x0 + 1, y0, self.wrap);
+                let c01 = texture.texel(x0, y0 + 1, self.wrap);
+                let c11 = texture.texel(x0 + 1, y0 + 1, self.wrap);
+
+                #[inline]
+                fn lerp(a: f32, b: f32, t: f32) -> f32 {
+                    a + (b - a) * t
+                }
+
+                Vector4::new(lerp(lerp(c00.x, c10.x, tx), lerp(c01.x, c11.x, tx), ty),
+                            lerp(lerp(c00.y, c10.y, tx), lerp(c01.y, c11.y, tx), ty),
+                            lerp(lerp(c00.z, c10.z, tx), lerp(c01.z, c11.z, tx), ty),
+                            lerp(lerp(c00.w, c10.w, tx), lerp(c01.w, c11.w, tx), ty))
+            }
+        }
+    }
+}
+
+/// A `Texture` paired with the `Sampler` a shader reads it through, e.g.
+/// `samplers.tex0.sample(uv)`. Holds the texture behind an `Arc` so it can be
+/// bound into a per-frame `Samplers` value without borrowing from `App`, and
+/// shared across the rasterizer's worker threads.
+pub struct BoundTexture {
+    texture: Arc<Texture>,
+    sampler: Sampler
+}
+
+impl BoundTexture {
+    pub fn new(texture: Arc<Texture>, sampler: Sampler) -> Self {
+        BoundTexture { texture: texture, sampler: sampler }
+    }
+
+    pub fn sample(&self, uv: Vector2<f32>) -> Vector4<f32> {
+        self.sampler.sample(&self.texture, uv)
+    }
+}